@@ -1,10 +1,13 @@
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take, take_while1},
+    bytes::complete::{tag, take, take_while},
+    character::complete::multispace0,
     character::is_digit,
-    combinator::iterator,
+    combinator::{iterator, opt},
+    sequence::delimited,
     IResult,
 };
+use std::fmt;
 use std::io;
 
 type Input<'a> = &'a [u8];
@@ -12,9 +15,48 @@ type Token<'a> = &'a [u8];
 
 #[derive(Debug, PartialEq, Eq)]
 #[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum TokenTree<'a> {
     Node(Vec<TokenTree<'a>>),
-    Leaf(Token<'a>),
+    Leaf(#[cfg_attr(feature = "serde", serde(with = "leaf_bytes"))] Token<'a>),
+}
+
+/// `serde(with = ...)` helpers for a `Leaf`'s bytes, gated behind the `serde` feature. A leaf is
+/// encoded as `{"text": "..."}` when every byte is printable, or `{"hex": "0011ff"}` otherwise —
+/// the same printable/hex split `pretty` uses, just JSON-shaped instead of `·`-separated.
+#[cfg(feature = "serde")]
+mod leaf_bytes {
+    use super::{decode_hex, is_printable, leak, Token};
+    use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(untagged)]
+    enum Repr {
+        Text { text: String },
+        Hex { hex: String },
+    }
+
+    pub fn serialize<S: Serializer>(bytes: &Token<'_>, serializer: S) -> Result<S::Ok, S::Error> {
+        if bytes.iter().copied().all(is_printable) {
+            Repr::Text {
+                text: String::from_utf8_lossy(bytes).into_owned(),
+            }
+            .serialize(serializer)
+        } else {
+            let hex = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            Repr::Hex { hex }.serialize(serializer)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Token<'static>, D::Error> {
+        let bytes = match Repr::deserialize(deserializer)? {
+            Repr::Text { text } => text.into_bytes(),
+            Repr::Hex { hex } => {
+                decode_hex(hex.as_bytes()).map_err(|_| de::Error::custom("invalid hex digits"))?
+            }
+        };
+        Ok(leak(bytes))
+    }
 }
 
 impl<'a> From<Token<'a>> for TokenTree<'a> {
@@ -23,12 +65,78 @@ impl<'a> From<Token<'a>> for TokenTree<'a> {
     }
 }
 
-pub fn deserialize(input: Input) -> anyhow::Result<TokenTree> {
-    match ptree(input) {
-        Ok((_, result)) => Ok(result),
-        Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => anyhow::bail!("{:?}", e),
-        Err(nom::Err::Incomplete(_)) => unreachable!(),
+impl<'a> TokenTree<'a> {
+    /// The children of a `Node`, or `None` if this is a `Leaf`.
+    pub fn children(&self) -> Option<&[TokenTree<'a>]> {
+        match self {
+            TokenTree::Node(children) => Some(children),
+            TokenTree::Leaf(_) => None,
+        }
+    }
+
+    /// The bytes of a `Leaf`, or `None` if this is a `Node`.
+    pub fn as_leaf(&self) -> Option<Token<'a>> {
+        match self {
+            TokenTree::Leaf(bytes) => Some(*bytes),
+            TokenTree::Node(_) => None,
+        }
+    }
+
+    /// Whether this is a `Node` whose first child is `Leaf(key)`, e.g. `(comment "foo")` is
+    /// named `comment`.
+    pub fn is_named(&self, key: &[u8]) -> bool {
+        self.children().and_then(|c| c.first()).and_then(TokenTree::as_leaf) == Some(key)
     }
+
+    /// The first direct child named `key`, e.g. `named(b"comment")` on
+    /// `(private-key (comment "foo"))` returns the `(comment "foo")` subtree.
+    pub fn named(&self, key: &[u8]) -> Option<&TokenTree<'a>> {
+        self.children()?.iter().find(|child| child.is_named(key))
+    }
+
+    /// Walk a sequence of direct-child lookups starting from `self`, e.g. `get_path(&[b"protected",
+    /// b"protected-at"])` on `(private-key (protected (protected-at "...")))` finds the nested
+    /// `(protected-at "...")` grandchild. Unlike `named`, which only looks one level deep,
+    /// `get_path` chains lookups through nested nodes — it doesn't check or care what `self` is
+    /// named, so it works the same whether the root is headed `private-key`,
+    /// `protected-private-key`, or anything else.
+    pub fn get_path(&self, path: &[&[u8]]) -> Option<&TokenTree<'a>> {
+        path.iter().try_fold(self, |node, key| node.named(key))
+    }
+}
+
+/// An error parsing the canonical S-expression encoding, carrying the byte offset into the
+/// original input at which the problem was found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum ParseError {
+    #[error("unexpected byte {found:#04x} at offset {offset}")]
+    UnexpectedByte { offset: usize, found: u8 },
+    #[error("length field overflows usize at offset {offset}")]
+    LengthOverflow { offset: usize },
+    #[error("truncated token at offset {offset}: need {need} bytes, have {have}")]
+    TruncatedToken {
+        offset: usize,
+        need: usize,
+        have: usize,
+    },
+    #[error("unbalanced parenthesis at offset {offset}")]
+    UnbalancedParen { offset: usize },
+    #[error("trailing data at offset {offset}")]
+    TrailingData { offset: usize },
+}
+
+fn offset(original: Input, remaining: Input) -> usize {
+    original.len() - remaining.len()
+}
+
+pub fn deserialize(input: Input) -> Result<TokenTree, ParseError> {
+    let (rest, tree) = ptree(input, input)?;
+    if !rest.is_empty() {
+        return Err(ParseError::TrailingData {
+            offset: offset(input, rest),
+        });
+    }
+    Ok(tree)
 }
 
 pub fn serialize(tree: &TokenTree, writer: &mut impl io::Write) -> io::Result<()> {
@@ -49,30 +157,473 @@ pub fn serialize(tree: &TokenTree, writer: &mut impl io::Write) -> io::Result<()
     Ok(())
 }
 
-fn ptree(input: Input) -> IResult<Input, TokenTree> {
-    alt((pnode, pleaf))(input)
+fn ptree<'a>(
+    original: Input<'a>,
+    input: Input<'a>,
+) -> Result<(Input<'a>, TokenTree<'a>), ParseError> {
+    match input.first() {
+        Some(b'(') => pnode(original, input),
+        Some(_) => pleaf(original, input),
+        None => Err(ParseError::TruncatedToken {
+            offset: offset(original, input),
+            need: 1,
+            have: 0,
+        }),
+    }
+}
+
+fn pnode<'a>(
+    original: Input<'a>,
+    input: Input<'a>,
+) -> Result<(Input<'a>, TokenTree<'a>), ParseError> {
+    let mut rest = &input[1..]; // skip the opening '('
+    let mut children = Vec::new();
+    loop {
+        match rest.first() {
+            Some(b')') => return Ok((&rest[1..], TokenTree::Node(children))),
+            Some(_) => {
+                let (next, child) = ptree(original, rest)?;
+                children.push(child);
+                rest = next;
+            }
+            None => {
+                return Err(ParseError::UnbalancedParen {
+                    offset: offset(original, input),
+                })
+            }
+        }
+    }
 }
 
-fn pnode(input: Input) -> IResult<Input, TokenTree> {
+fn pleaf<'a>(
+    original: Input<'a>,
+    input: Input<'a>,
+) -> Result<(Input<'a>, TokenTree<'a>), ParseError> {
+    let (input, token) = ptoken(original, input)?;
+    Ok((input, token.into()))
+}
+
+fn ptoken<'a>(original: Input<'a>, input: Input<'a>) -> Result<(Input<'a>, Token<'a>), ParseError> {
+    let digit_len = input.iter().take_while(|&&b| is_digit(b)).count();
+    if digit_len == 0 {
+        return match input.first() {
+            Some(&found) => Err(ParseError::UnexpectedByte {
+                offset: offset(original, input),
+                found,
+            }),
+            None => Err(ParseError::TruncatedToken {
+                offset: offset(original, input),
+                need: 1,
+                have: 0,
+            }),
+        };
+    }
+    let mut size: usize = 0;
+    for &b in &input[..digit_len] {
+        size = size
+            .checked_mul(10)
+            .and_then(|s| s.checked_add((b - b'0') as usize))
+            .ok_or(ParseError::LengthOverflow {
+                offset: offset(original, input),
+            })?;
+    }
+    let rest = &input[digit_len..];
+    let rest = match rest.first() {
+        Some(b':') => &rest[1..],
+        Some(&found) => {
+            return Err(ParseError::UnexpectedByte {
+                offset: offset(original, rest),
+                found,
+            })
+        }
+        None => {
+            return Err(ParseError::TruncatedToken {
+                offset: offset(original, rest),
+                need: 1,
+                have: 0,
+            })
+        }
+    };
+    if rest.len() < size {
+        return Err(ParseError::TruncatedToken {
+            offset: offset(original, rest),
+            need: size,
+            have: rest.len(),
+        });
+    }
+    Ok((&rest[size..], &rest[..size]))
+}
+
+/// A nom-compatible wrapper around [`ptoken`] for use inside the advanced-format grammar, which
+/// still reports errors as `anyhow` diagnostics rather than [`ParseError`].
+fn ptoken_nom(input: Input) -> IResult<Input, Token> {
+    ptoken(input, input).map_err(|_| {
+        nom::Err::Error(nom::error::Error::new(input, nom::error::ErrorKind::Digit))
+    })
+}
+
+/// Leak `bytes` to obtain a `'static` slice.
+///
+/// The advanced encoding can require unescaping/decoding (quoted strings, `#hex#` and `|base64|`
+/// literals), so its leaves aren't simply a view into the original input the way the canonical
+/// encoding's are. `gpg-keytag` parses one file, does one operation and exits, so the handful of
+/// small allocations this leaks per run isn't worth threading an owned variant through `Token`.
+fn leak(bytes: Vec<u8>) -> &'static [u8] {
+    Box::leak(bytes.into_boxed_slice())
+}
+
+fn is_printable(b: u8) -> bool {
+    b.is_ascii_graphic() || b == b' '
+}
+
+fn is_bare_token(bytes: &[u8]) -> bool {
+    match bytes.split_first() {
+        Some((&head, tail)) => {
+            head.is_ascii_alphabetic()
+                && tail
+                    .iter()
+                    .all(|&c| c.is_ascii_alphanumeric() || b"-./_:*+=".contains(&c))
+        }
+        None => false,
+    }
+}
+
+fn decode_hex(digits: &[u8]) -> Result<Vec<u8>, ()> {
+    if !digits.len().is_multiple_of(2) {
+        return Err(());
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let hi = (pair[0] as char).to_digit(16).ok_or(())?;
+            let lo = (pair[1] as char).to_digit(16).ok_or(())?;
+            Ok(((hi as u8) << 4) | lo as u8)
+        })
+        .collect()
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn encode_base64(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b0 = chunk[0];
+        let b1 = *chunk.get(1).unwrap_or(&0);
+        let b2 = *chunk.get(2).unwrap_or(&0);
+        out.push(BASE64_ALPHABET[(b0 >> 2) as usize] as char);
+        out.push(BASE64_ALPHABET[(((b0 & 0x03) << 4) | (b1 >> 4)) as usize] as char);
+        out.push(if chunk.len() > 1 {
+            BASE64_ALPHABET[(((b1 & 0x0f) << 2) | (b2 >> 6)) as usize] as char
+        } else {
+            '='
+        });
+        out.push(if chunk.len() > 2 {
+            BASE64_ALPHABET[(b2 & 0x3f) as usize] as char
+        } else {
+            '='
+        });
+    }
+    out
+}
+
+fn decode_base64(data: &[u8]) -> Result<Vec<u8>, ()> {
+    fn value(c: u8) -> Result<u8, ()> {
+        BASE64_ALPHABET
+            .iter()
+            .position(|&b| b == c)
+            .map(|i| i as u8)
+            .ok_or(())
+    }
+    let filtered: Vec<u8> = data
+        .iter()
+        .copied()
+        .filter(|&c| !(c as char).is_whitespace())
+        .collect();
+    if !filtered.len().is_multiple_of(4) {
+        return Err(());
+    }
+    let mut out = Vec::new();
+    for chunk in filtered.chunks(4) {
+        let pad = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if pad > 2 || chunk[..4 - pad].contains(&b'=') {
+            return Err(());
+        }
+        let mut vals = [0u8; 4];
+        for (i, &c) in chunk.iter().enumerate() {
+            vals[i] = if c == b'=' { 0 } else { value(c)? };
+        }
+        out.push((vals[0] << 2) | (vals[1] >> 4));
+        if pad < 2 {
+            out.push((vals[1] << 4) | (vals[2] >> 2));
+        }
+        if pad < 1 {
+            out.push((vals[2] << 6) | vals[3]);
+        }
+    }
+    Ok(out)
+}
+
+/// Parse the *advanced* S-expression format: whitespace between tokens is insignificant, and a
+/// leaf may be a bare token, a C-escaped quoted string, a `#hex#` literal, a `|base64|` literal,
+/// or a canonical `len:data` token (optionally preceded by a `[display-hint]`).
+pub fn parse_advanced(input: Input) -> anyhow::Result<TokenTree> {
+    match ptree_advanced(input) {
+        Ok((rest, result)) => {
+            // multispace0 never fails (it matches zero or more), so trailing whitespace after the
+            // top-level expression is insignificant but anything else is trailing garbage.
+            let (rest, _) = multispace0::<_, nom::error::Error<Input>>(rest)
+                .expect("multispace0 is infallible");
+            if !rest.is_empty() {
+                anyhow::bail!("trailing data after advanced-format expression");
+            }
+            Ok(result)
+        }
+        Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => anyhow::bail!("{:?}", e),
+        Err(nom::Err::Incomplete(_)) => unreachable!(),
+    }
+}
+
+fn ptree_advanced(input: Input) -> IResult<Input, TokenTree> {
+    let (input, _) = multispace0(input)?;
+    alt((pnode_advanced, pleaf_advanced))(input)
+}
+
+fn pnode_advanced(input: Input) -> IResult<Input, TokenTree> {
     let (input, _) = tag(b"(")(input)?;
-    let mut it = iterator(input, ptree);
+    let mut it = iterator(input, ptree_advanced);
     let children: Vec<_> = it.collect();
     let (input, ()) = it.finish()?;
+    let (input, _) = multispace0(input)?;
     let (input, _) = tag(b")")(input)?;
     Ok((input, TokenTree::Node(children)))
 }
 
-fn pleaf(input: Input) -> IResult<Input, TokenTree> {
-    let (input, v) = ptoken(input)?;
-    Ok((input, v.into()))
+fn pleaf_advanced(input: Input) -> IResult<Input, TokenTree> {
+    let (input, _) = opt(pdisplay_hint)(input)?;
+    let (input, token) = alt((pquoted, phex, pbase64, ptoken_nom, pbare_token))(input)?;
+    Ok((input, token.into()))
+}
+
+fn pdisplay_hint(input: Input) -> IResult<Input, Input> {
+    delimited(tag(b"["), take_while(|c| c != b']'), tag(b"]"))(input)
+}
+
+fn pbare_token(input: Input) -> IResult<Input, Token> {
+    match input.split_first() {
+        Some((&head, _)) if head.is_ascii_alphabetic() => {
+            let end = input
+                .iter()
+                .position(|&c| !(c.is_ascii_alphanumeric() || b"-./_:*+=".contains(&c)))
+                .unwrap_or(input.len());
+            Ok((&input[end..], &input[..end]))
+        }
+        _ => Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Alpha,
+        ))),
+    }
+}
+
+fn phex(input: Input) -> IResult<Input, Token> {
+    let (input, digits) = delimited(
+        tag(b"#"),
+        take_while(|c: u8| c.is_ascii_hexdigit() || (c as char).is_whitespace()),
+        tag(b"#"),
+    )(input)?;
+    let digits: Vec<u8> = digits
+        .iter()
+        .copied()
+        .filter(|c| !(*c as char).is_whitespace())
+        .collect();
+    let bytes = decode_hex(&digits).map_err(|_| {
+        nom::Err::Failure(nom::error::Error::new(input, nom::error::ErrorKind::HexDigit))
+    })?;
+    Ok((input, leak(bytes)))
+}
+
+fn pbase64(input: Input) -> IResult<Input, Token> {
+    let (input, data) = delimited(tag(b"|"), take_while(|c| c != b'|'), tag(b"|"))(input)?;
+    let bytes = decode_base64(data).map_err(|_| {
+        nom::Err::Failure(nom::error::Error::new(input, nom::error::ErrorKind::Verify))
+    })?;
+    Ok((input, leak(bytes)))
+}
+
+fn pquoted(input: Input) -> IResult<Input, Token> {
+    let (mut rest, _) = tag(b"\"")(input)?;
+    let mut out = Vec::new();
+    loop {
+        match rest.first() {
+            None => {
+                return Err(nom::Err::Error(nom::error::Error::new(
+                    rest,
+                    nom::error::ErrorKind::Eof,
+                )))
+            }
+            Some(b'"') => {
+                rest = &rest[1..];
+                break;
+            }
+            Some(b'\\') => {
+                let (next, byte) = pescape(&rest[1..])?;
+                out.push(byte);
+                rest = next;
+            }
+            Some(&c) => {
+                out.push(c);
+                rest = &rest[1..];
+            }
+        }
+    }
+    Ok((rest, leak(out)))
+}
+
+fn pescape(input: Input) -> IResult<Input, u8> {
+    match input.split_first() {
+        Some((b'n', rest)) => Ok((rest, b'\n')),
+        Some((b't', rest)) => Ok((rest, b'\t')),
+        Some((b'r', rest)) => Ok((rest, b'\r')),
+        Some((b'0', rest)) => Ok((rest, 0)),
+        Some((b'\\', rest)) => Ok((rest, b'\\')),
+        Some((b'"', rest)) => Ok((rest, b'"')),
+        Some((b'\'', rest)) => Ok((rest, b'\'')),
+        Some((b'x', rest)) => {
+            let (rest, hex) = take(2usize)(rest)?;
+            let byte = decode_hex(hex).map_err(|_| {
+                nom::Err::Failure(nom::error::Error::new(rest, nom::error::ErrorKind::HexDigit))
+            })?;
+            Ok((rest, byte[0]))
+        }
+        _ => Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::EscapedTransform,
+        ))),
+    }
+}
+
+/// Serialize `tree` as the *advanced* format: printable leaves that look like bare tokens are
+/// emitted unquoted, other printable leaves as a C-escaped quoted string, and binary leaves as a
+/// `#hex#` literal.
+pub fn to_advanced(tree: &TokenTree, writer: &mut impl io::Write) -> io::Result<()> {
+    match tree {
+        TokenTree::Leaf(bytes) => write_advanced_leaf(bytes, writer)?,
+        TokenTree::Node(children) => {
+            writer.write_all(b"(")?;
+            for (i, child) in children.iter().enumerate() {
+                if i > 0 {
+                    writer.write_all(b" ")?;
+                }
+                to_advanced(child, writer)?;
+            }
+            writer.write_all(b")")?;
+        }
+    }
+    Ok(())
+}
+
+fn write_advanced_leaf(bytes: &[u8], writer: &mut impl io::Write) -> io::Result<()> {
+    if is_bare_token(bytes) {
+        writer.write_all(bytes)
+    } else if bytes.iter().copied().all(is_printable) {
+        writer.write_all(b"\"")?;
+        for &b in bytes {
+            match b {
+                b'"' => writer.write_all(b"\\\"")?,
+                b'\\' => writer.write_all(b"\\\\")?,
+                _ => writer.write_all(&[b])?,
+            }
+        }
+        writer.write_all(b"\"")
+    } else {
+        writer.write_all(b"#")?;
+        for &b in bytes {
+            write!(writer, "{:02X}", b)?;
+        }
+        writer.write_all(b"#")
+    }
+}
+
+/// Render `tree` as an indented, human-readable block: each node is a parenthesized block with
+/// one child per line, and each leaf is printed as UTF-8 text when every byte is printable, or as
+/// a `·`-separated hex dump (e.g. `48·65·6c·6c·6f`) otherwise.
+///
+/// GPG private-key files are full of large binary MPI leaves, so the `Debug` output is
+/// unreadable; this makes it possible to see where `comment` sits relative to `private-key`,
+/// `protected`, etc.
+pub fn pretty(tree: &TokenTree, writer: &mut impl io::Write) -> io::Result<()> {
+    write_pretty(tree, writer, 0)
+}
+
+fn write_pretty(tree: &TokenTree, writer: &mut impl io::Write, depth: usize) -> io::Result<()> {
+    match tree {
+        TokenTree::Leaf(bytes) => write_pretty_leaf(bytes, writer),
+        TokenTree::Node(children) => {
+            writer.write_all(b"(")?;
+            for child in children {
+                writer.write_all(b"\n")?;
+                write_indent(writer, depth + 1)?;
+                write_pretty(child, writer, depth + 1)?;
+            }
+            if !children.is_empty() {
+                writer.write_all(b"\n")?;
+                write_indent(writer, depth)?;
+            }
+            writer.write_all(b")")
+        }
+    }
+}
+
+fn write_indent(writer: &mut impl io::Write, depth: usize) -> io::Result<()> {
+    for _ in 0..depth {
+        writer.write_all(b"  ")?;
+    }
+    Ok(())
+}
+
+fn write_pretty_leaf(bytes: &[u8], writer: &mut impl io::Write) -> io::Result<()> {
+    if bytes.iter().copied().all(is_printable) {
+        writer.write_all(bytes)
+    } else {
+        for (i, b) in bytes.iter().enumerate() {
+            if i > 0 {
+                writer.write_all("·".as_bytes())?;
+            }
+            write!(writer, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+/// A `Display` wrapper around [`pretty`], for use in `format!`/`{}`/`println!`.
+pub struct Pretty<'a, 'b>(pub &'b TokenTree<'a>);
+
+impl<'a, 'b> fmt::Display for Pretty<'a, 'b> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut buf = Vec::new();
+        pretty(self.0, &mut buf).map_err(|_| fmt::Error)?;
+        f.write_str(&String::from_utf8_lossy(&buf))
+    }
+}
+
+/// Wrap the canonical encoding of `tree` as *transport* format: `{` + base64(canonical) + `}`.
+pub fn to_transport(tree: &TokenTree, writer: &mut impl io::Write) -> io::Result<()> {
+    let mut canonical = Vec::new();
+    serialize(tree, &mut canonical)?;
+    writer.write_all(b"{")?;
+    writer.write_all(encode_base64(&canonical).as_bytes())?;
+    writer.write_all(b"}")
 }
 
-fn ptoken(input: Input) -> IResult<Input, Token> {
-    let (input, size) = take_while1(is_digit)(input)?;
-    let size: usize = std::str::from_utf8(size).unwrap().parse().unwrap();
-    let (input, _) = tag(b":")(input)?;
-    let (input, result) = take(size)(input)?;
-    Ok((input, result))
+/// Parse the *transport* format and decode the canonical S-expression it wraps.
+pub fn parse_transport(input: Input) -> anyhow::Result<TokenTree> {
+    let inner = input
+        .strip_prefix(b"{")
+        .and_then(|rest| rest.strip_suffix(b"}"))
+        .ok_or_else(|| anyhow::anyhow!("not a transport-format s-expression"))?;
+    let canonical = decode_base64(inner)
+        .map_err(|_| anyhow::anyhow!("invalid base64 in transport encoding"))?;
+    Ok(deserialize(leak(canonical))?)
 }
 
 #[cfg(test)]
@@ -81,7 +632,7 @@ mod tests {
 
     #[test]
     fn parse_leaf() {
-        let input = b"12:foobarbazbizqux";
+        let input = b"12:foobarbazbiz";
         let v = deserialize(&input[..]).unwrap();
         assert_eq!(v, Leaf(b"foobarbazbiz"));
     }
@@ -153,4 +704,313 @@ mod tests {
         .unwrap();
         assert_eq!(&output, b"(6:foobar(7:comment3:qux))");
     }
+
+    #[test]
+    fn deserialize_length_overflow_does_not_panic() {
+        let input = b"99999999999999999999:foobar";
+        let err = deserialize(&input[..]).unwrap_err();
+        assert_eq!(err, ParseError::LengthOverflow { offset: 0 });
+    }
+
+    #[test]
+    fn deserialize_truncated_token() {
+        let input = b"6:foo";
+        let err = deserialize(&input[..]).unwrap_err();
+        assert_eq!(
+            err,
+            ParseError::TruncatedToken {
+                offset: 2,
+                need: 6,
+                have: 3
+            }
+        );
+    }
+
+    #[test]
+    fn deserialize_unbalanced_paren() {
+        let input = b"(6:foobar";
+        let err = deserialize(&input[..]).unwrap_err();
+        assert_eq!(err, ParseError::UnbalancedParen { offset: 0 });
+    }
+
+    #[test]
+    fn deserialize_rejects_trailing_data() {
+        let input = b"(6:foobar)garbage";
+        let err = deserialize(&input[..]).unwrap_err();
+        assert_eq!(err, ParseError::TrailingData { offset: 10 });
+    }
+
+    #[test]
+    fn deserialize_unexpected_byte() {
+        let input = b"x";
+        let err = deserialize(&input[..]).unwrap_err();
+        assert_eq!(err, ParseError::UnexpectedByte { offset: 0, found: b'x' });
+    }
+
+    #[test]
+    fn parse_advanced_bare_tokens() {
+        let input = b"(private-key foobar)";
+        let v = parse_advanced(&input[..]).unwrap();
+        assert_eq!(v, Node(vec![Leaf(b"private-key"), Leaf(b"foobar")]));
+    }
+
+    #[test]
+    fn parse_advanced_ignores_whitespace() {
+        let input = b"(  foo\n  (bar   baz) )";
+        let v = parse_advanced(&input[..]).unwrap();
+        assert_eq!(
+            v,
+            Node(vec![Leaf(b"foo"), Node(vec![Leaf(b"bar"), Leaf(b"baz")])])
+        );
+    }
+
+    #[test]
+    fn parse_advanced_quoted_string_with_escapes() {
+        let input = b"\"ab\\ncd\"";
+        let v = parse_advanced(&input[..]).unwrap();
+        assert_eq!(v, Leaf(b"ab\ncd"));
+    }
+
+    #[test]
+    fn parse_advanced_hex_literal() {
+        let input = b"#48656C6C6F#";
+        let v = parse_advanced(&input[..]).unwrap();
+        assert_eq!(v, Leaf(b"Hello"));
+    }
+
+    #[test]
+    fn parse_advanced_base64_literal() {
+        let input = b"|aGVsbG8=|";
+        let v = parse_advanced(&input[..]).unwrap();
+        assert_eq!(v, Leaf(b"hello"));
+    }
+
+    #[test]
+    fn parse_advanced_base64_literal_rejects_truncated_group() {
+        // "aGVsbG8" is "hello" missing its final `=` padding character.
+        let input = b"|aGVsbG8|";
+        assert!(parse_advanced(&input[..]).is_err());
+    }
+
+    #[test]
+    fn parse_advanced_base64_literal_rejects_interior_padding() {
+        let input = b"|aG=sbG8=|";
+        assert!(parse_advanced(&input[..]).is_err());
+    }
+
+    #[test]
+    fn parse_advanced_verbatim_token() {
+        let input = b"(foo 6:foobar)";
+        let v = parse_advanced(&input[..]).unwrap();
+        assert_eq!(v, Node(vec![Leaf(b"foo"), Leaf(b"foobar")]));
+    }
+
+    #[test]
+    fn parse_advanced_skips_display_hint() {
+        let input = b"[text/plain]\"hi\"";
+        let v = parse_advanced(&input[..]).unwrap();
+        assert_eq!(v, Leaf(b"hi"));
+    }
+
+    #[test]
+    fn parse_advanced_rejects_trailing_data() {
+        let input = b"(foo bar)garbage";
+        assert!(parse_advanced(&input[..]).is_err());
+    }
+
+    #[test]
+    fn parse_advanced_allows_trailing_whitespace() {
+        let input = b"(foo bar) \n";
+        let v = parse_advanced(&input[..]).unwrap();
+        assert_eq!(v, Node(vec![Leaf(b"foo"), Leaf(b"bar")]));
+    }
+
+    #[test]
+    fn to_advanced_bare_token() {
+        let mut output: Vec<u8> = Vec::new();
+        to_advanced(&Leaf(b"foobar"), &mut output).unwrap();
+        assert_eq!(&output, b"foobar");
+    }
+
+    #[test]
+    fn to_advanced_quotes_non_token_printable() {
+        let mut output: Vec<u8> = Vec::new();
+        to_advanced(&Leaf(b"hello world"), &mut output).unwrap();
+        assert_eq!(&output, b"\"hello world\"");
+    }
+
+    #[test]
+    fn to_advanced_hex_for_binary() {
+        let mut output: Vec<u8> = Vec::new();
+        to_advanced(&Leaf(&[0, 1, 255]), &mut output).unwrap();
+        assert_eq!(&output, b"#0001FF#");
+    }
+
+    #[test]
+    fn to_advanced_node() {
+        let mut output: Vec<u8> = Vec::new();
+        to_advanced(
+            &Node(vec![Leaf(b"comment"), Leaf(b"hello world")]),
+            &mut output,
+        )
+        .unwrap();
+        assert_eq!(&output, b"(comment \"hello world\")");
+    }
+
+    #[test]
+    fn children_of_node() {
+        let tree = Node(vec![Leaf(b"foo"), Leaf(b"bar")]);
+        assert_eq!(tree.children(), Some(&[Leaf(b"foo"), Leaf(b"bar")][..]));
+    }
+
+    #[test]
+    fn children_of_leaf_is_none() {
+        assert_eq!(Leaf(b"foo").children(), None);
+    }
+
+    #[test]
+    fn as_leaf_of_leaf() {
+        assert_eq!(Leaf(b"foo").as_leaf(), Some(&b"foo"[..]));
+    }
+
+    #[test]
+    fn as_leaf_of_node_is_none() {
+        assert_eq!(Node(vec![]).as_leaf(), None);
+    }
+
+    #[test]
+    fn named_finds_matching_child() {
+        let tree = Node(vec![
+            Leaf(b"private-key"),
+            Node(vec![Leaf(b"notcomment"), Leaf(b"foobar")]),
+            Node(vec![Leaf(b"comment"), Leaf(b"qux")]),
+        ]);
+        assert_eq!(
+            tree.named(b"comment"),
+            Some(&Node(vec![Leaf(b"comment"), Leaf(b"qux")]))
+        );
+    }
+
+    #[test]
+    fn named_returns_none_when_missing() {
+        let tree = Node(vec![Leaf(b"private-key")]);
+        assert_eq!(tree.named(b"comment"), None);
+    }
+
+    #[test]
+    fn get_path_walks_nested_nodes() {
+        let tree = Node(vec![
+            Leaf(b"private-key"),
+            Node(vec![
+                Leaf(b"protected"),
+                Node(vec![Leaf(b"protected-at"), Leaf(b"qux")]),
+            ]),
+        ]);
+        assert_eq!(
+            tree.get_path(&[b"protected", b"protected-at"]),
+            Some(&Node(vec![Leaf(b"protected-at"), Leaf(b"qux")]))
+        );
+    }
+
+    #[test]
+    fn get_path_returns_none_on_missing_segment() {
+        let tree = Node(vec![
+            Leaf(b"private-key"),
+            Node(vec![Leaf(b"comment"), Leaf(b"qux")]),
+        ]);
+        assert_eq!(tree.get_path(&[b"protected", b"protected-at"]), None);
+    }
+
+    #[test]
+    fn get_path_ignores_own_tag() {
+        // get_path doesn't care what the root is named — it works the same under
+        // `private-key`, `protected-private-key`, etc.
+        let tree = Node(vec![
+            Leaf(b"protected-private-key"),
+            Node(vec![Leaf(b"comment"), Leaf(b"qux")]),
+        ]);
+        assert_eq!(
+            tree.get_path(&[b"comment"]),
+            Some(&Node(vec![Leaf(b"comment"), Leaf(b"qux")]))
+        );
+    }
+
+    #[test]
+    fn pretty_printable_leaf() {
+        let mut output: Vec<u8> = Vec::new();
+        pretty(&Leaf(b"foobar"), &mut output).unwrap();
+        assert_eq!(&output, b"foobar");
+    }
+
+    #[test]
+    fn pretty_binary_leaf_as_hex() {
+        let mut output: Vec<u8> = Vec::new();
+        pretty(&Leaf(&[0x48, 0x65, 0x6c, 0x6c, 0x6f, 0xff]), &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "48·65·6c·6c·6f·ff");
+    }
+
+    #[test]
+    fn pretty_indents_nested_nodes() {
+        let tree = Node(vec![
+            Leaf(b"private-key"),
+            Node(vec![Leaf(b"comment"), Leaf(b"foobar")]),
+        ]);
+        let mut output: Vec<u8> = Vec::new();
+        pretty(&tree, &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "(\n  private-key\n  (\n    comment\n    foobar\n  )\n)"
+        );
+    }
+
+    #[test]
+    fn pretty_empty_node() {
+        let mut output: Vec<u8> = Vec::new();
+        pretty(&Node(vec![]), &mut output).unwrap();
+        assert_eq!(&output, b"()");
+    }
+
+    #[test]
+    fn pretty_display_wrapper_matches_pretty() {
+        let tree = Node(vec![Leaf(b"comment"), Leaf(b"foobar")]);
+        let mut output: Vec<u8> = Vec::new();
+        pretty(&tree, &mut output).unwrap();
+        assert_eq!(Pretty(&tree).to_string(), String::from_utf8(output).unwrap());
+    }
+
+    #[test]
+    fn transport_round_trip() {
+        let tree = Node(vec![Leaf(b"comment"), Leaf(b"foobar")]);
+        let mut output: Vec<u8> = Vec::new();
+        to_transport(&tree, &mut output).unwrap();
+        assert_eq!(output.first(), Some(&b'{'));
+        let roundtripped = parse_transport(&output).unwrap();
+        assert_eq!(roundtripped, tree);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_encodes_printable_leaf_as_text() {
+        let json = serde_json::to_string(&Leaf(b"foobar")).unwrap();
+        assert_eq!(json, r#"{"Leaf":{"text":"foobar"}}"#);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_encodes_binary_leaf_as_hex() {
+        let json = serde_json::to_string(&Leaf(&[0, 1, 255])).unwrap();
+        assert_eq!(json, r#"{"Leaf":{"hex":"0001ff"}}"#);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_round_trip_through_canonical_encoding() {
+        let input = b"(6:foobar(7:comment3:qux))";
+        let tree = deserialize(&input[..]).unwrap();
+        let json = serde_json::to_string(&tree).unwrap();
+        let roundtripped: TokenTree = serde_json::from_str(&json).unwrap();
+        let mut output = Vec::new();
+        serialize(&roundtripped, &mut output).unwrap();
+        assert_eq!(&output, input);
+    }
 }
@@ -3,29 +3,101 @@ use keyfile::TokenTree;
 
 const GPG_COMMENT_FIELD: &[u8] = b"comment";
 
-const USAGE: &str = r#"gpg-keytags: add a comment to your GPG key.
+const USAGE: &str = r#"gpg-keytags: inspect and edit fields in your GPG key.
 
 Usage:
 
     gpg-keytag <keyfile> [<comment>]
+    gpg-keytag get <keyfile> <field>
+    gpg-keytag set <keyfile> <field> <value>
+    gpg-keytag del <keyfile> <field>
+    gpg-keytag list <keyfile>
+    gpg-keytag dump <keyfile> [--advanced|--transport]
+    gpg-keytag --json <keyfile>
 
-You must specify the path to a private key file (usually in .gnupg/private-keys-v1.d/). If only
-<keyfile> is given, gpg-keytag will print the current tag. If <comment> is given as well,
-gpg-keytag will replace the current tag with the new comment.
+You must specify the path to a private key file (usually in .gnupg/private-keys-v1.d/).
+
+The bare `<keyfile> [<comment>]` form is shorthand for getting or setting the `comment` field. The
+`get`/`set`/`del` subcommands work on any top-level named field (e.g. `comment`, `protected-at`),
+`list` prints the names of all top-level named fields in the file, and `--json` (requires the
+`serde` feature) dumps the whole parsed structure as JSON for external tooling.
+
+`dump` accepts a key file in canonical, advanced or transport encoding and prints it back out: as
+an indented tree by default, or re-encoded as `--advanced`/`--transport` S-expressions.
 "#;
 
 fn main() -> anyhow::Result<()> {
     let args: Vec<String> = std::env::args().collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
     match args.as_slice() {
+        #[cfg(feature = "serde")]
+        [_, "--json", keyfile] => {
+            let content = std::fs::read(keyfile)?;
+            let tree = keyfile::deserialize(&content)?;
+            println!("{}", serde_json::to_string_pretty(&tree)?);
+        }
+        [_, "get", keyfile, field] => {
+            let content = std::fs::read(keyfile)?;
+            let tree = keyfile::deserialize(&content)?;
+            println!(
+                "{}",
+                get_field(&tree, field.as_bytes())
+                    .as_deref()
+                    .unwrap_or("(none)")
+            );
+        }
+        [_, "set", keyfile, field, value] => {
+            let content = std::fs::read(keyfile)?;
+            let mut tree = keyfile::deserialize(&content)?;
+            upsert_field(&mut tree, field.as_bytes(), value);
+            let mut writer = std::fs::File::create(keyfile)?;
+            keyfile::serialize(&tree, &mut writer)?;
+        }
+        [_, "del", keyfile, field] => {
+            let content = std::fs::read(keyfile)?;
+            let mut tree = keyfile::deserialize(&content)?;
+            remove_field(&mut tree, field.as_bytes());
+            let mut writer = std::fs::File::create(keyfile)?;
+            keyfile::serialize(&tree, &mut writer)?;
+        }
+        [_, "list", keyfile] => {
+            let content = std::fs::read(keyfile)?;
+            let tree = keyfile::deserialize(&content)?;
+            for field in list_fields(&tree) {
+                println!("{}", field);
+            }
+        }
+        [_, "dump", keyfile] => {
+            let content = std::fs::read(keyfile)?;
+            let tree = read_tree(&content)?;
+            println!("{}", keyfile::Pretty(&tree));
+        }
+        [_, "dump", keyfile, "--advanced"] => {
+            let content = std::fs::read(keyfile)?;
+            let tree = read_tree(&content)?;
+            keyfile::to_advanced(&tree, &mut std::io::stdout())?;
+            println!();
+        }
+        [_, "dump", keyfile, "--transport"] => {
+            let content = std::fs::read(keyfile)?;
+            let tree = read_tree(&content)?;
+            keyfile::to_transport(&tree, &mut std::io::stdout())?;
+            println!();
+        }
         [_, keyfile] => {
             let content = std::fs::read(keyfile)?;
             let tree = keyfile::deserialize(&content)?;
-            println!("{}", get_comment(&tree).as_deref().unwrap_or("(none)"));
+            println!(
+                "{}",
+                get_field(&tree, GPG_COMMENT_FIELD)
+                    .as_deref()
+                    .unwrap_or("(none)")
+            );
         }
         [_, keyfile, comment] => {
             let content = std::fs::read(keyfile)?;
             let mut tree = keyfile::deserialize(&content)?;
-            upsert_comment(&mut tree, &comment);
+            upsert_field(&mut tree, GPG_COMMENT_FIELD, comment);
             let mut writer = std::fs::File::create(keyfile)?;
             keyfile::serialize(&tree, &mut writer)?;
         }
@@ -34,44 +106,61 @@ fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-fn get_comment(tt: &TokenTree) -> Option<String> {
+/// Parse `content` as whichever S-expression encoding it's in, trying transport (`{...}`),
+/// canonical, then advanced in turn — `dump` is the one command meant to accept a key file in any
+/// of the three encodings `keyfile` knows how to read.
+fn read_tree(content: &[u8]) -> anyhow::Result<TokenTree> {
+    if content.starts_with(b"{") {
+        return keyfile::parse_transport(content);
+    }
+    match keyfile::deserialize(content) {
+        Ok(tree) => Ok(tree),
+        Err(_) => keyfile::parse_advanced(content),
+    }
+}
+
+fn get_field(tt: &TokenTree, field: &[u8]) -> Option<String> {
+    let node = tt.get_path(&[field])?;
+    let value = node.children()?.get(1)?.as_leaf()?;
+    Some(String::from_utf8_lossy(value).to_string())
+}
+
+fn upsert_field<'a>(tt: &mut TokenTree<'a>, field: &'a [u8], value: &'a str) {
     use keyfile::TokenTree::*;
-    let values = if let Node(children) = tt {
+    let children = if let Node(children) = tt {
         children
     } else {
-        return None;
+        return;
     };
-    for value in values.iter().skip(1) {
-        match value {
-            Node(xs) if xs.get(0) == Some(&Leaf(GPG_COMMENT_FIELD)) => match xs.get(1) {
-                Some(Leaf(bs)) => return Some(String::from_utf8_lossy(bs).to_string()),
-                _ => return None,
-            },
-            _ => {}
-        }
+    // Look for an existing field and replace it with the new value
+    if let Some(existing) = children.iter_mut().find(|child| child.is_named(field)) {
+        *existing = Node(vec![Leaf(field), Leaf(value.as_bytes())]);
+        return;
     }
-    None
+    // Didn't find the field, insert at the end
+    children.push(Node(vec![Leaf(field), Leaf(value.as_bytes())]));
 }
 
-fn upsert_comment<'a>(tt: &mut TokenTree<'a>, value: &'a str) {
-    use keyfile::TokenTree::*;
-    let children = if let Node(children) = tt {
+fn remove_field(tt: &mut TokenTree, field: &[u8]) {
+    let children = if let TokenTree::Node(children) = tt {
         children
     } else {
         return;
     };
-    // Look for an existing comment and replace it with the new one
-    for child in children.iter_mut() {
-        match child {
-            Node(xs) if xs.get(0) == Some(&Leaf(GPG_COMMENT_FIELD)) => {
-                *xs = vec![Leaf(GPG_COMMENT_FIELD), Leaf(value.as_bytes())];
-                return;
-            }
-            _ => {}
-        }
-    }
-    // Didn't find a comment, insert at the end
-    children.push(Node(vec![Leaf(GPG_COMMENT_FIELD), Leaf(value.as_bytes())]));
+    children.retain(|child| !child.is_named(field));
+}
+
+fn list_fields(tt: &TokenTree) -> Vec<String> {
+    let children = match tt.children() {
+        Some(children) => children,
+        None => return Vec::new(),
+    };
+    children
+        .iter()
+        .skip(1)
+        .filter_map(|child| child.children()?.first()?.as_leaf())
+        .map(|name| String::from_utf8_lossy(name).to_string())
+        .collect()
 }
 
 #[cfg(test)]
@@ -80,7 +169,7 @@ mod tests {
     use keyfile::TokenTree::*;
 
     #[test]
-    fn get_comment_exist() {
+    fn get_field_exist() {
         let tree = TokenTree::Node(vec![
             TokenTree::Leaf(b"private-key"),
             TokenTree::Node(vec![
@@ -93,20 +182,20 @@ mod tests {
                 TokenTree::Leaf(b"foobar"),
             ]),
         ]);
-        assert_eq!(get_comment(&tree), Some(String::from("foobar")));
+        assert_eq!(get_field(&tree, b"comment"), Some(String::from("foobar")));
     }
 
     #[test]
-    fn get_comment_missing_value() {
+    fn get_field_missing_value() {
         let tree = TokenTree::Node(vec![
             TokenTree::Leaf(b"private-key"),
             TokenTree::Node(vec![TokenTree::Leaf(b"comment")]),
         ]);
-        assert_eq!(get_comment(&tree), None);
+        assert_eq!(get_field(&tree, b"comment"), None);
     }
 
     #[test]
-    fn get_comment_missing() {
+    fn get_field_missing() {
         let tree = TokenTree::Node(vec![
             TokenTree::Leaf(b"private-key"),
             TokenTree::Node(vec![
@@ -114,23 +203,37 @@ mod tests {
                 TokenTree::Leaf(b"foobar"),
             ]),
         ]);
-        assert_eq!(get_comment(&tree), None);
+        assert_eq!(get_field(&tree, b"comment"), None);
     }
 
     #[test]
-    fn get_comment_leaf() {
+    fn get_field_leaf() {
         let tree = TokenTree::Leaf(b"qux");
-        assert_eq!(get_comment(&tree), None);
+        assert_eq!(get_field(&tree, b"comment"), None);
+    }
+
+    #[test]
+    fn get_field_protected_private_key_head() {
+        // GnuPG private-key files may be headed `protected-private-key` or
+        // `shadowed-private-key` rather than plain `private-key`.
+        let tree = TokenTree::Node(vec![
+            TokenTree::Leaf(b"protected-private-key"),
+            TokenTree::Node(vec![
+                TokenTree::Leaf(b"comment"),
+                TokenTree::Leaf(b"foobar"),
+            ]),
+        ]);
+        assert_eq!(get_field(&tree, b"comment"), Some(String::from("foobar")));
     }
 
     #[test]
-    fn upsert_comment_insert() {
+    fn upsert_field_insert() {
         let mut tree = Node(vec![
             Leaf(b"private-key"),
             Node(vec![Leaf(b"notcomment")]),
             Leaf(b"otherthing"),
         ]);
-        upsert_comment(&mut tree, "foobar");
+        upsert_field(&mut tree, b"comment", "foobar");
         assert_eq!(
             tree,
             Node(vec![
@@ -143,13 +246,13 @@ mod tests {
     }
 
     #[test]
-    fn upsert_comment_update() {
+    fn upsert_field_update() {
         let mut tree = Node(vec![
             Leaf(b"private-key"),
             Node(vec![Leaf(b"comment"), Leaf(b"quux")]),
             Leaf(b"otherthing"),
         ]);
-        upsert_comment(&mut tree, "foobar");
+        upsert_field(&mut tree, b"comment", "foobar");
         assert_eq!(
             tree,
             Node(vec![
@@ -159,4 +262,90 @@ mod tests {
             ])
         );
     }
+
+    #[test]
+    fn remove_field_existing() {
+        let mut tree = Node(vec![
+            Leaf(b"private-key"),
+            Node(vec![Leaf(b"comment"), Leaf(b"quux")]),
+            Leaf(b"otherthing"),
+        ]);
+        remove_field(&mut tree, b"comment");
+        assert_eq!(
+            tree,
+            Node(vec![Leaf(b"private-key"), Leaf(b"otherthing")])
+        );
+    }
+
+    #[test]
+    fn remove_field_missing_is_noop() {
+        let mut tree = Node(vec![Leaf(b"private-key"), Leaf(b"otherthing")]);
+        remove_field(&mut tree, b"comment");
+        assert_eq!(
+            tree,
+            Node(vec![Leaf(b"private-key"), Leaf(b"otherthing")])
+        );
+    }
+
+    #[test]
+    fn list_fields_top_level() {
+        let tree = Node(vec![
+            Leaf(b"private-key"),
+            Node(vec![Leaf(b"rsa"), Leaf(b"foobar")]),
+            Node(vec![Leaf(b"comment"), Leaf(b"qux")]),
+        ]);
+        assert_eq!(
+            list_fields(&tree),
+            vec![String::from("rsa"), String::from("comment")]
+        );
+    }
+
+    #[test]
+    fn list_fields_of_leaf_is_empty() {
+        let tree = Leaf(b"qux");
+        assert!(list_fields(&tree).is_empty());
+    }
+
+    #[test]
+    fn list_fields_protected_private_key_head() {
+        let tree = Node(vec![
+            Leaf(b"protected-private-key"),
+            Node(vec![Leaf(b"rsa"), Leaf(b"foobar")]),
+            Node(vec![Leaf(b"comment"), Leaf(b"qux")]),
+        ]);
+        assert_eq!(
+            list_fields(&tree),
+            vec![String::from("rsa"), String::from("comment")]
+        );
+    }
+
+    #[test]
+    fn set_then_get_round_trip_on_protected_private_key() {
+        // Regression test: get_field must read from wherever upsert_field/remove_field write,
+        // regardless of the top node's head leaf.
+        let mut tree = Node(vec![
+            Leaf(b"protected-private-key"),
+            Node(vec![Leaf(b"rsa"), Leaf(b"foobar")]),
+        ]);
+        upsert_field(&mut tree, b"comment", "hello");
+        assert_eq!(get_field(&tree, b"comment"), Some(String::from("hello")));
+    }
+
+    #[test]
+    fn read_tree_canonical() {
+        let tree = read_tree(b"(7:comment3:qux)").unwrap();
+        assert_eq!(tree, Node(vec![Leaf(b"comment"), Leaf(b"qux")]));
+    }
+
+    #[test]
+    fn read_tree_advanced() {
+        let tree = read_tree(b"(comment qux)").unwrap();
+        assert_eq!(tree, Node(vec![Leaf(b"comment"), Leaf(b"qux")]));
+    }
+
+    #[test]
+    fn read_tree_transport() {
+        let tree = read_tree(b"{KDc6Y29tbWVudDM6cXV4KQ==}").unwrap();
+        assert_eq!(tree, Node(vec![Leaf(b"comment"), Leaf(b"qux")]));
+    }
 }